@@ -3,9 +3,13 @@
 //! script applications.  It's particularly useful if you need to tell other
 //! applications to execute certain functionality.
 //!
-//! Currently only JavaScript is supported.  Parameters passed to it show up
-//! as `$params` and the return value from the script (as returned with the
-//! `return` keyword) is deserialized later.
+//! JavaScript is the default language, but any language registered with the
+//! OSA system (such as AppleScript) can be used through [`Script`].  For
+//! JavaScript the parameters passed to it show up as `$params` and the return
+//! value from the script (as returned with the `return` keyword) is
+//! deserialized later.  Other languages cannot consume the JSON `$params`
+//! blob, so for those the code is passed through untouched and the caller is
+//! responsible for any serialization.
 //!
 //! # Example
 //!
@@ -54,8 +58,12 @@
 use std::process;
 use std::io;
 use std::fmt;
+use std::thread;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use std::string::FromUtf8Error;
-use std::io::Write;
+use std::io::{Write, Read, BufRead};
 use std::error;
 
 extern crate serde;
@@ -70,12 +78,111 @@ use serde::de::DeserializeOwned;
 pub enum Error {
     Io(io::Error),
     Json(serde_json::Error),
-    Script(String),
+    /// A script raised an error.
+    ///
+    /// `osascript` reports failures as `execution error: <message>
+    /// (<errorName>) (<number>)` and JXA additionally reports a line number for
+    /// syntax errors.  These are parsed out so that callers can react to a
+    /// specific condition (for instance automation not being authorized, which
+    /// is `code` `-1743`) rather than matching on the message string.
+    Script {
+        message: String,
+        code: Option<i32>,
+        name: Option<String>,
+        line: Option<u32>,
+    },
+    /// The script did not finish within the configured timeout and was killed.
+    Timeout,
 }
 
-/// Holds an apple flavoured JavaScript
-pub struct JavaScript {
+impl Error {
+    /// Builds a [`Error::Script`] by parsing the raw stderr text emitted by
+    /// `osascript`.
+    fn script(raw: &str) -> Error {
+        let raw = raw.trim();
+        // Strip the leading `execution error: ` / `syntax error: ` marker if
+        // present; what remains starts with the human readable message.
+        let mut rest = raw;
+        for prefix in &["execution error: ", "syntax error: "] {
+            if let Some(pos) = rest.find(prefix) {
+                rest = &rest[pos + prefix.len()..];
+                break;
+            }
+        }
+
+        // Trailing parenthesised groups carry the error name and/or number,
+        // e.g. `… (errAEEventNotPermitted) (-1743)`.  Peel them off the end.
+        let mut message = rest.trim().to_string();
+        let mut code = None;
+        let mut name = None;
+        while message.ends_with(')') {
+            let open = match message.rfind('(') {
+                Some(pos) => pos,
+                None => break,
+            };
+            let inner = message[open + 1..message.len() - 1].trim().to_string();
+            if let Ok(num) = inner.parse::<i32>() {
+                code = Some(num);
+            } else if !inner.is_empty() {
+                name = Some(inner);
+            } else {
+                break;
+            }
+            message = message[..open].trim_end().to_string();
+        }
+
+        // JXA reports syntax errors with a `line N` fragment somewhere in the
+        // message; pull the first such number out if present.
+        let line = raw.find("line ").and_then(|pos| {
+            raw[pos + "line ".len()..]
+                .split(|c: char| !c.is_digit(10))
+                .next()
+                .and_then(|digits| digits.parse::<u32>().ok())
+        });
+
+        Error::Script {
+            message: message,
+            code: code,
+            name: name,
+            line: line,
+        }
+    }
+}
+
+/// A language understood by the OSA system.
+///
+/// `JavaScript` and `AppleScript` are always available; `Other` can be used to
+/// refer to any additional language registered with the system by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    JavaScript,
+    AppleScript,
+    Other(&'static str),
+}
+
+impl Language {
+    /// The name as it is passed to the `-l` argument of `osascript`.
+    fn as_arg(&self) -> &str {
+        match *self {
+            Language::JavaScript => "JavaScript",
+            Language::AppleScript => "AppleScript",
+            Language::Other(name) => name,
+        }
+    }
+}
+
+/// Holds a script together with the OSA language it is written in.
+pub struct Script {
     code: String,
+    language: Language,
+}
+
+/// Holds an apple flavoured JavaScript.
+///
+/// This is a thin wrapper around [`Script`] that fixes the language to
+/// JavaScript.
+pub struct JavaScript {
+    script: Script,
 }
 
 impl From<io::Error> for Error {
@@ -92,7 +199,7 @@ impl From<serde_json::Error> for Error {
 
 impl From<FromUtf8Error> for Error {
     fn from(err: FromUtf8Error) -> Error {
-        Error::Script(format!("UTF-8 Error: {}", err))
+        Error::script(&format!("UTF-8 Error: {}", err))
     }
 }
 
@@ -101,7 +208,8 @@ impl error::Error for Error {
         match *self {
             Error::Io(ref err) => err.description(),
             Error::Json(ref err) => err.description(),
-            Error::Script(..) => "script error",
+            Error::Script { .. } => "script error",
+            Error::Timeout => "script timed out",
         }
     }
 }
@@ -111,7 +219,20 @@ impl fmt::Display for Error {
         match *self {
             Error::Io(ref err) => write!(f, "script io error: {}", err),
             Error::Json(ref err) => write!(f, "script json error: {}", err),
-            Error::Script(ref msg) => write!(f, "script error: {}", msg),
+            Error::Script { ref message, code, ref name, line } => {
+                write!(f, "script error: {}", message)?;
+                if let Some(ref name) = *name {
+                    write!(f, " ({})", name)?;
+                }
+                if let Some(code) = code {
+                    write!(f, " (code {})", code)?;
+                }
+                if let Some(line) = line {
+                    write!(f, " on line {}", line)?;
+                }
+                Ok(())
+            }
+            Error::Timeout => write!(f, "script timed out"),
         }
     }
 }
@@ -119,42 +240,405 @@ impl fmt::Display for Error {
 #[derive(Serialize)]
 struct EmptyParams {}
 
-fn wrap_code<S: Serialize>(code: &str, params: S) -> Result<String, Error> {
-    let mut buf: Vec<u8> = vec![];
-    write!(&mut buf, "var $params = ")?;
-    serde_json::to_writer(&mut buf, &params)?;
-    write!(&mut buf, ";JSON.stringify((function() {{{};return null;}})());", code)?;
-    Ok(String::from_utf8(buf)?)
+/// The largest wrapped script that is still passed inline through `-e`.
+///
+/// Anything bigger is piped through stdin instead to stay clear of the OS
+/// argument-length limit (`ARG_MAX`, typically a few hundred kilobytes).
+const MAX_INLINE_CODE: usize = 64 * 1024;
+
+/// Controls the environment `osascript` is executed in.
+///
+/// This is threaded through [`Script::execute_with_options`] and lets a caller
+/// override the working directory, inject environment variables, and enforce a
+/// wall-clock timeout.  A timeout is particularly useful because a target
+/// application can show a modal dialog and never hand control back.
+#[derive(Default)]
+pub struct ExecOptions {
+    current_dir: Option<PathBuf>,
+    envs: Vec<(OsString, OsString)>,
+    timeout: Option<Duration>,
+}
+
+impl ExecOptions {
+    /// Creates an empty set of options equivalent to the default behavior.
+    pub fn new() -> ExecOptions {
+        ExecOptions::default()
+    }
+
+    /// Sets the working directory for the spawned process.
+    pub fn current_dir<P: Into<PathBuf>>(mut self, dir: P) -> ExecOptions {
+        self.current_dir = Some(dir.into());
+        self
+    }
+
+    /// Injects or overrides a single environment variable.
+    pub fn env<K: Into<OsString>, V: Into<OsString>>(mut self, key: K, value: V) -> ExecOptions {
+        self.envs.push((key.into(), value.into()));
+        self
+    }
+
+    /// Kills the process and returns [`Error::Timeout`] if the script has not
+    /// finished within the given duration.
+    pub fn timeout(mut self, timeout: Duration) -> ExecOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+/// Waits for a child to finish, killing and reaping it if the deadline passes.
+///
+/// The output pipes are drained on separate threads so that a chatty script
+/// cannot deadlock by filling a pipe buffer while we poll for completion.
+fn wait_timeout(mut child: process::Child, timeout: Duration) -> Result<process::Output, Error> {
+    let out_handle = child.stdout.take().map(|mut s| thread::spawn(move || {
+        let mut buf = vec![];
+        let _ = s.read_to_end(&mut buf);
+        buf
+    }));
+    let err_handle = child.stderr.take().map(|mut s| thread::spawn(move || {
+        let mut buf = vec![];
+        let _ = s.read_to_end(&mut buf);
+        buf
+    }));
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait()? {
+            Some(status) => break status,
+            None => {
+                if Instant::now() >= deadline {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(Error::Timeout);
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    };
+
+    let stdout = out_handle.map_or_else(Vec::new, |h| h.join().unwrap_or_default());
+    let stderr = err_handle.map_or_else(Vec::new, |h| h.join().unwrap_or_default());
+    Ok(process::Output { status: status, stdout: stdout, stderr: stderr })
+}
+
+/// A builder for passing several independently-typed arguments to a script.
+///
+/// Each pushed value is serialized on its own and the collected values are
+/// assembled into the `$params` object, so there is no need to define a
+/// one-off struct for every call site:
+///
+/// ```ignore
+/// let args = ScriptArgs::new()
+///     .push("title", &title)?
+///     .push("buttons", &buttons)?;
+/// let rv: Reply = script.execute_with_params(args)?;
+/// ```
+pub struct ScriptArgs {
+    map: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ScriptArgs {
+    /// Creates an empty argument set.
+    pub fn new() -> ScriptArgs {
+        ScriptArgs {
+            map: serde_json::Map::new(),
+        }
+    }
+
+    /// Serializes and stores a value under the given name.
+    pub fn push<S: Serialize>(mut self, name: &str, value: S) -> Result<ScriptArgs, Error> {
+        self.map.insert(name.to_string(), serde_json::to_value(value)?);
+        Ok(self)
+    }
+}
+
+impl serde::Serialize for ScriptArgs {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        self.map.serialize(serializer)
+    }
+}
+
+fn wrap_code<S: Serialize>(language: Language, code: &str, params: S) -> Result<String, Error> {
+    match language {
+        Language::JavaScript => {
+            let mut buf: Vec<u8> = vec![];
+            write!(&mut buf, "var $params = ")?;
+            serde_json::to_writer(&mut buf, &params)?;
+            write!(&mut buf, ";JSON.stringify((function() {{{};return null;}})());", code)?;
+            Ok(String::from_utf8(buf)?)
+        }
+        // Other OSA languages such as AppleScript cannot consume the JSON
+        // `$params` blob, so the code is handed to `osascript` untouched and
+        // the caller is responsible for any serialization.
+        _ => Ok(code.to_string()),
+    }
+}
+
+impl Script {
+    /// Creates a new script from the given code in the given language.
+    pub fn new(language: Language, code: &str) -> Script {
+        Script {
+            code: code.to_string(),
+            language: language,
+        }
+    }
+
+    /// Executes the script and does not pass any arguments.
+    pub fn execute<'a, D: DeserializeOwned>(&self) -> Result<D, Error> {
+        self.execute_with_params(EmptyParams {})
+    }
+
+    /// Executes the script and passes the provided arguments.
+    ///
+    /// The arguments are only injected as `$params` for JavaScript; for other
+    /// languages they are ignored.
+    pub fn execute_with_params<'a, S: Serialize, D: DeserializeOwned>(&self, params: S)
+        -> Result<D, Error>
+    {
+        self.execute_with_options(params, &ExecOptions::new())
+    }
+
+    /// Executes the script with explicit control over the execution
+    /// environment (see [`ExecOptions`]).
+    pub fn execute_with_options<'a, S: Serialize, D: DeserializeOwned>(
+        &self, params: S, options: &ExecOptions) -> Result<D, Error>
+    {
+        let (stdout, _) = self.run(params, options)?;
+        Ok(serde_json::from_slice(&stdout)?)
+    }
+
+    /// Executes the script and captures the log output alongside the value.
+    ///
+    /// JXA scripts routinely emit diagnostics with `console.log(...)`, which
+    /// `osascript` writes to stderr.  The regular execute methods discard that
+    /// text on success; this variant returns both the deserialized value and
+    /// the captured log so it can be observed even when the run succeeds.
+    pub fn execute_capturing<'a, D: DeserializeOwned>(&self) -> Result<(D, String), Error> {
+        self.execute_capturing_with_params(EmptyParams {})
+    }
+
+    /// Like [`Script::execute_capturing`] but passes the provided arguments.
+    pub fn execute_capturing_with_params<'a, S: Serialize, D: DeserializeOwned>(&self, params: S)
+        -> Result<(D, String), Error>
+    {
+        let (stdout, stderr) = self.run(params, &ExecOptions::new())?;
+        Ok((serde_json::from_slice(&stdout)?, String::from_utf8(stderr)?))
+    }
+
+    /// Runs the script and returns the raw stdout and stderr on success.
+    fn run<'a, S: Serialize>(&self, params: S, options: &ExecOptions)
+        -> Result<(Vec<u8>, Vec<u8>), Error>
+    {
+        let wrapped_code = wrap_code(self.language, &self.code, params)?;
+
+        let mut cmd = process::Command::new("osascript");
+        cmd.arg("-l").arg(self.language.as_arg());
+        cmd.stdout(process::Stdio::piped());
+        cmd.stderr(process::Stdio::piped());
+
+        // Small scripts are passed inline through `-e`; larger payloads would
+        // overflow the OS argument-length limit, so they are piped to stdin
+        // and read back from `/dev/stdin`, which lifts any size ceiling.
+        let inline = wrapped_code.len() <= MAX_INLINE_CODE;
+        if inline {
+            cmd.arg("-e").arg(&wrapped_code);
+        } else {
+            cmd.arg("/dev/stdin").stdin(process::Stdio::piped());
+        }
+
+        if let Some(ref dir) = options.current_dir {
+            cmd.current_dir(dir);
+        }
+        for &(ref key, ref value) in &options.envs {
+            cmd.env(key, value);
+        }
+
+        let mut child = cmd.spawn()?;
+        if !inline {
+            child.stdin.take().expect("child stdin was piped")
+                .write_all(wrapped_code.as_bytes())?;
+        }
+
+        let output = match options.timeout {
+            Some(timeout) => wait_timeout(child, timeout)?,
+            None => child.wait_with_output()?,
+        };
+
+        if output.status.success() {
+            Ok((output.stdout, output.stderr))
+        } else {
+            Err(Error::script(&String::from_utf8(output.stderr)?))
+        }
+    }
 }
 
 impl JavaScript {
     /// Creates a new script from the given code.
     pub fn new(code: &str) -> JavaScript {
         JavaScript {
-            code: code.to_string(),
+            script: Script::new(Language::JavaScript, code),
         }
     }
 
     /// Executes the script and does not pass any arguments.
     pub fn execute<'a, D: DeserializeOwned>(&self) -> Result<D, Error> {
-        self.execute_with_params(EmptyParams {})
+        self.script.execute()
     }
 
     /// Executes the script and passes the provided arguments.
     pub fn execute_with_params<'a, S: Serialize, D: DeserializeOwned>(&self, params: S)
         -> Result<D, Error>
     {
-        let wrapped_code = wrap_code(&self.code, params)?;
-        let output = process::Command::new("osascript")
+        self.script.execute_with_params(params)
+    }
+
+    /// Executes the script and captures the log output alongside the value.
+    pub fn execute_capturing<'a, D: DeserializeOwned>(&self) -> Result<(D, String), Error> {
+        self.script.execute_capturing()
+    }
+
+    /// Like [`JavaScript::execute_capturing`] but passes the provided arguments.
+    pub fn execute_capturing_with_params<'a, S: Serialize, D: DeserializeOwned>(&self, params: S)
+        -> Result<(D, String), Error>
+    {
+        self.script.execute_capturing_with_params(params)
+    }
+}
+
+/// The JavaScript program that drives a [`Session`].
+///
+/// The user supplied handler body is substituted for the marker.  The program
+/// reads one line-delimited JSON request of the shape `{"id", "params"}` at a
+/// time, runs the handler with `$params` bound, and writes back a single line
+/// of the shape `{"id", "ok", "value"}` (or `{"id", "ok": false, "error"}`).
+/// Because `JSON.stringify` escapes newlines the responses are always exactly
+/// one line, so no extra framing is required.
+const SESSION_BOOTSTRAP: &'static str = r#"ObjC.import('Foundation');
+(function() {
+    var $handler = function($params) { __OSASCRIPT_HANDLER_BODY__;
+return null; };
+    var stdin = $.NSFileHandle.fileHandleWithStandardInput;
+    var stdout = $.NSFileHandle.fileHandleWithStandardOutput;
+    function writeLine(str) {
+        stdout.writeData($(str + "\n").dataUsingEncoding($.NSUTF8StringEncoding));
+    }
+    var buffer = "";
+    while (true) {
+        var nl = buffer.indexOf("\n");
+        if (nl < 0) {
+            var data = stdin.availableData;
+            if (data.length === 0) { break; }
+            buffer += $.NSString.alloc.initWithDataEncoding(data, $.NSUTF8StringEncoding).js;
+            continue;
+        }
+        var line = buffer.slice(0, nl);
+        buffer = buffer.slice(nl + 1);
+        if (!line) { continue; }
+        var req = JSON.parse(line);
+        var resp;
+        try {
+            resp = { id: req.id, ok: true, value: $handler(req.params) };
+        } catch (e) {
+            resp = { id: req.id, ok: false, error: String(e) };
+        }
+        writeLine(JSON.stringify(resp));
+    }
+})();"#;
+
+#[derive(Deserialize)]
+struct SessionResponse {
+    id: u64,
+    ok: bool,
+    #[serde(default)]
+    value: serde_json::Value,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// A persistent JavaScript interpreter session.
+///
+/// Spawning a fresh `osascript` process for every tiny script is expensive
+/// when a program issues many of them in a loop.  A `Session` instead keeps a
+/// single long-lived `osascript` child alive and dispatches each request to it
+/// over a line-delimited JSON protocol.
+///
+/// The handler body passed to [`Session::new`] is evaluated once per
+/// [`Session::dispatch`] call with the dispatched arguments bound to `$params`,
+/// exactly like the body of [`JavaScript`].
+pub struct Session {
+    child: process::Child,
+    stdin: process::ChildStdin,
+    stdout: io::BufReader<process::ChildStdout>,
+    next_id: u64,
+}
+
+impl Session {
+    /// Spawns a new session that runs the given handler body for each request.
+    pub fn new(handler: &str) -> Result<Session, Error> {
+        let bootstrap = SESSION_BOOTSTRAP.replace("__OSASCRIPT_HANDLER_BODY__", handler);
+        let mut child = process::Command::new("osascript")
             .arg("-l")
             .arg("JavaScript")
             .arg("-e")
-            .arg(&wrapped_code)
-            .output()?;
-        if output.status.success() {
-            Ok(serde_json::from_slice(&output.stdout)?)
-        } else {
-            Err(Error::Script(String::from_utf8(output.stderr)?))
+            .arg(&bootstrap)
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().expect("child stdin was piped");
+        let stdout = child.stdout.take().expect("child stdout was piped");
+        Ok(Session {
+            child: child,
+            stdin: stdin,
+            stdout: io::BufReader::new(stdout),
+            next_id: 0,
+        })
+    }
+
+    /// Dispatches a request to the session and deserializes its return value.
+    pub fn dispatch<S: Serialize, D: DeserializeOwned>(&mut self, params: S)
+        -> Result<D, Error>
+    {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut req: Vec<u8> = vec![];
+        write!(&mut req, "{{\"id\":{},\"params\":", id)?;
+        serde_json::to_writer(&mut req, &params)?;
+        req.extend_from_slice(b"}\n");
+        self.stdin.write_all(&req)?;
+        self.stdin.flush()?;
+
+        loop {
+            let mut line = String::new();
+            if self.stdout.read_line(&mut line)? == 0 {
+                return Err(Error::script("osascript session terminated unexpectedly"));
+            }
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let resp: SessionResponse = serde_json::from_str(line)?;
+            // Ignore responses for requests we are no longer waiting on.
+            if resp.id != id {
+                continue;
+            }
+            if resp.ok {
+                return Ok(serde_json::from_value(resp.value)?);
+            } else {
+                return Err(Error::script(&resp.error.unwrap_or_else(|| "script error".into())));
+            }
         }
     }
 }
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Stdin is still open here, so the bootstrap loop would keep waiting in
+        // its `availableData` read; kill the child and then reap it so no
+        // zombie is left behind.
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}